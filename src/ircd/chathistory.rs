@@ -0,0 +1,262 @@
+use anyhow::{Error, Result};
+use irc::client::prelude::Command;
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    ruma::events::{AnySyncTimelineEvent, SyncMessageLikeEvent},
+    ruma::events::room::message::SyncRoomMessageEvent,
+    room::{MessagesOptions, Room},
+};
+
+use crate::ircd::proto::{tags_for, Capabilities, IrcMessage, IrcMessageType};
+use crate::ircd::IrcClient;
+use crate::matrix::room_mappings::Mappings;
+
+/// clients asking for more than this get clamped down, so a typo'd
+/// limit can't make us replay the whole room history in one go.
+const MAX_CHATHISTORY_LIMIT: usize = 200;
+const DEFAULT_CHATHISTORY_LIMIT: usize = 50;
+
+/// handle a `CHATHISTORY <subcommand> <target> ...` line.
+///
+/// `LATEST`/`BEFORE`/`AFTER`/`AROUND` take one selector (4 args);
+/// `BETWEEN` takes two, one on either side of the range (5 args).
+/// `AROUND` still just falls back to `BEFORE` semantics on its one
+/// selector, and `BETWEEN`'s upper bound is only honoured when it's a
+/// `timestamp=` selector, since `matrix_sdk`'s `/messages` only gives us
+/// one-sided pagination - the other end is applied client-side as a cutoff.
+pub async fn handle_chathistory(
+    irc: &IrcClient,
+    mappings: &Mappings,
+    client: &matrix_sdk::Client,
+    args: &[String],
+) -> Result<()> {
+    let (subcommand, target, selector, end_selector, limit) = match args {
+        [subcommand, target, selector, limit] => (
+            subcommand.as_str(),
+            target.as_str(),
+            selector.as_str(),
+            None,
+            limit,
+        ),
+        [subcommand, target, selector, end_selector, limit]
+            if subcommand.eq_ignore_ascii_case("BETWEEN") =>
+        {
+            (
+                subcommand.as_str(),
+                target.as_str(),
+                selector.as_str(),
+                Some(end_selector.as_str()),
+                limit,
+            )
+        }
+        _ => return Err(Error::msg("CHATHISTORY: wrong number of arguments")),
+    };
+    let limit = limit
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_CHATHISTORY_LIMIT)
+        .min(MAX_CHATHISTORY_LIMIT);
+
+    let room_id = mappings
+        .room_id_for_target(target)
+        .await
+        .ok_or_else(|| Error::msg(format!("CHATHISTORY: unknown target {}", target)))?;
+    let room = client
+        .get_room(&room_id)
+        .ok_or_else(|| Error::msg(format!("CHATHISTORY: room {} not found", room_id)))?;
+
+    let mut options = MessagesOptions::backward();
+    options.limit = (limit as u32).into();
+    let forward = matches!(subcommand, "AFTER" | "BETWEEN");
+    match subcommand {
+        "LATEST" => (), // default: backward from the live end of the timeline
+        "BEFORE" | "AROUND" => options.from = Some(selector_to_token(selector)?),
+        "AFTER" | "BETWEEN" => {
+            options.from = Some(selector_to_token(selector)?);
+            // matrix_sdk's /messages only paginates one direction at a
+            // time, so AFTER/BETWEEN switch this query to forward.
+            options.dir = matrix_sdk::ruma::api::Direction::Forward;
+        }
+        other => return Err(Error::msg(format!("CHATHISTORY: unknown subcommand {}", other))),
+    }
+
+    let end_ts = end_selector.map(selector_to_ts).transpose()?.flatten();
+    let events = room.messages(options).await?.chunk;
+    let events = match end_ts {
+        Some(end_ts) => events
+            .into_iter()
+            .take_while(|event| match text_of(event) {
+                Some((_, _, ts)) => ts <= end_ts,
+                None => true,
+            })
+            .collect(),
+        None => events,
+    };
+    send_batch(irc, target, &room, events, limit, forward, &irc.capabilities).await
+}
+
+/// a `BEFORE`/`AFTER`/`AROUND` selector is either `timestamp=<ms>` or
+/// `msgid=<pagination token>`. Only `msgid=` is actually a
+/// `matrix_sdk` pagination token; a `timestamp=` value is a plain
+/// millisecond integer and sending it to `room.messages` as `from`
+/// would be a bogus anchor, not an honest "closest event" lookup, so
+/// it's rejected explicitly instead of silently mistranslated. Clients
+/// that care about anchoring on a timestamp should use `msgid=` (same
+/// as most other bridges).
+fn selector_to_token(selector: &str) -> Result<String> {
+    let (kind, value) = selector
+        .split_once('=')
+        .ok_or_else(|| Error::msg(format!("CHATHISTORY: invalid selector {}", selector)))?;
+    if kind != "msgid" {
+        return Err(Error::msg(format!(
+            "CHATHISTORY: {}= selectors aren't supported as a pagination anchor, use msgid=",
+            kind
+        )));
+    }
+    Ok(value.to_string())
+}
+
+/// only a `timestamp=<ms>` selector can be turned into a cutoff we can
+/// apply ourselves; a `msgid=` selector has no meaning outside
+/// matrix_sdk's own pagination, so it's not usable as `BETWEEN`'s upper
+/// bound and just disables client-side clipping.
+fn selector_to_ts(selector: &str) -> Result<Option<matrix_sdk::ruma::MilliSecondsSinceUnixEpoch>> {
+    let (kind, value) = selector
+        .split_once('=')
+        .ok_or_else(|| Error::msg(format!("CHATHISTORY: invalid selector {}", selector)))?;
+    if kind != "timestamp" {
+        return Ok(None);
+    }
+    let ms = value
+        .parse::<u64>()
+        .map_err(|_| Error::msg(format!("CHATHISTORY: invalid timestamp selector {}", selector)))?;
+    Ok(Some(matrix_sdk::ruma::MilliSecondsSinceUnixEpoch(
+        ms.try_into()?,
+    )))
+}
+
+async fn send_batch(
+    irc: &IrcClient,
+    target: &str,
+    room: &Room,
+    mut events: Vec<TimelineEvent>,
+    limit: usize,
+    forward: bool,
+    capabilities: &Capabilities,
+) -> Result<()> {
+    events.truncate(limit);
+    // dir=b (LATEST/BEFORE/AROUND) gives us newest-first, so flip it to
+    // replay oldest-first like real backlog; dir=f (AFTER/BETWEEN) is
+    // already chronological and must be left alone.
+    if !forward {
+        events.reverse();
+    }
+
+    let batch_ref = format!("matrirc-{}", target);
+    send_raw(irc, format!(":matrirc BATCH +{} chathistory {}", batch_ref, target)).await?;
+    for event in events {
+        if let Some((sender, body, origin_server_ts)) = text_of(&event) {
+            let nick = room
+                .get_member(&sender)
+                .await
+                .ok()
+                .flatten()
+                .map(|member| member.name().to_string())
+                .unwrap_or_else(|| sender.to_string());
+            let message: irc::proto::Message = IrcMessage {
+                message_type: IrcMessageType::Privmsg,
+                from: target.into(),
+                target: irc.nick.clone(),
+                message: format!("<{}> {}", nick, body),
+                tags: tags_for(capabilities, origin_server_ts),
+            }
+            .into();
+            irc.send(message).await?;
+        }
+    }
+    send_raw(irc, format!(":matrirc BATCH -{}", batch_ref)).await
+}
+
+/// send a raw line that doesn't fit the `Command` enum (e.g. `BATCH`
+/// framing) through the client's normal message channel.
+async fn send_raw(irc: &IrcClient, line: String) -> Result<()> {
+    irc.send(line.parse::<irc::proto::Message>()?).await
+}
+
+/// pull sender/body/timestamp out of a timeline event if it's a plain
+/// `m.room.message`, ignoring state events, reactions, etc.
+fn text_of(
+    event: &TimelineEvent,
+) -> Option<(
+    matrix_sdk::ruma::OwnedUserId,
+    String,
+    matrix_sdk::ruma::MilliSecondsSinceUnixEpoch,
+)> {
+    let event = event.event.deserialize().ok()?;
+    if let AnySyncTimelineEvent::MessageLike(
+        matrix_sdk::ruma::events::AnySyncMessageLikeEvent::RoomMessage(
+            SyncRoomMessageEvent::Original(message),
+        ),
+    ) = event
+    {
+        Some((
+            message.sender,
+            message.content.body().to_string(),
+            message.origin_server_ts,
+        ))
+    } else {
+        None
+    }
+}
+
+/// recognise a `CHATHISTORY` line, which arrives as the generic
+/// `Command::Raw` variant, and return its arguments for
+/// [`handle_chathistory`]; any other command gives `None`.
+pub fn is_chathistory(command: &Command) -> Option<Vec<String>> {
+    if let Command::Raw(cmd, args) = command {
+        if cmd.eq_ignore_ascii_case("CHATHISTORY") {
+            return Some(args.clone());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selector_to_token_accepts_msgid() {
+        assert_eq!(selector_to_token("msgid=abc123").unwrap(), "abc123");
+    }
+
+    #[test]
+    fn selector_to_token_rejects_timestamp() {
+        assert!(selector_to_token("timestamp=1700000000000").is_err());
+    }
+
+    #[test]
+    fn selector_to_token_rejects_missing_equals() {
+        assert!(selector_to_token("abc123").is_err());
+    }
+
+    #[test]
+    fn selector_to_ts_parses_timestamp() {
+        let ts = selector_to_ts("timestamp=1700000000000").unwrap().unwrap();
+        assert_eq!(ts.get(), 1700000000000u64.try_into().unwrap());
+    }
+
+    #[test]
+    fn selector_to_ts_ignores_msgid() {
+        assert!(selector_to_ts("msgid=abc123").unwrap().is_none());
+    }
+
+    #[test]
+    fn selector_to_ts_rejects_non_numeric_timestamp() {
+        assert!(selector_to_ts("timestamp=not-a-number").is_err());
+    }
+
+    #[test]
+    fn selector_to_ts_rejects_missing_equals() {
+        assert!(selector_to_ts("abc123").is_err());
+    }
+}
@@ -9,14 +9,28 @@ use tokio_util::codec::Framed;
 // difference here
 use futures::TryStreamExt;
 
-use crate::{ircd::proto, matrix, state};
+use crate::{ircd::proto, ircd::proto::Capabilities, matrix, state};
+
+/// capabilities matrirc is able to negotiate with clients, advertised
+/// as-is in response to `CAP LS`.
+const SUPPORTED_CAPS: &[&str] = &[
+    "server-time",
+    "message-tags",
+    "batch",
+    "draft/chathistory",
+];
 
 pub async fn auth_loop(
     stream: &mut Framed<TcpStream, IrcCodec>,
-) -> Result<(String, String, matrix_sdk::Client)> {
+) -> Result<(String, String, matrix_sdk::Client, Capabilities)> {
     let mut client_nick = None;
     let mut client_user = None;
     let mut client_pass = None;
+    let mut capabilities = Capabilities::default();
+    // once the client sends `CAP LS`, it owns when negotiation is done: we
+    // must not treat login as complete until it explicitly sends `CAP END`,
+    // even if NICK/USER/PASS came in first.
+    let mut negotiating_caps = false;
     while let Some(event) = stream.try_next().await? {
         trace!("auth loop: got {:?}", event);
         match event.command {
@@ -24,12 +38,15 @@ pub async fn auth_loop(
             Command::PASS(pass) => client_pass = Some(pass),
             Command::USER(user, _, _) => {
                 client_user = Some(user);
-                break;
+                if !negotiating_caps {
+                    break;
+                }
             }
-            Command::CAP(_, _, Some(code), _) => {
-                // required for recent-ish versions of irssi
-                if code == "302" {
-                    proto::send_raw_msg(stream, ":matrirc CAP * LS :").await?;
+            Command::CAP(_, ref subcommand, ref param, _) => {
+                negotiating_caps =
+                    handle_cap(stream, subcommand, param.as_deref(), &mut capabilities).await?;
+                if !negotiating_caps && client_nick.is_some() && client_user.is_some() {
+                    break;
                 }
             }
             _ => (), // ignore
@@ -44,12 +61,76 @@ pub async fn auth_loop(
             Some(session) => matrix_restore_session(stream, &nick, &pass, session).await?,
             None => matrix_login_loop(stream, &nick, &pass).await?,
         };
-        Ok((nick, user, client))
+        Ok((nick, user, client, capabilities))
     } else {
         Err(Error::msg("nick or pass wasn't set for client!"))
     }
 }
 
+/// handle one `CAP` line, returning whether negotiation is still ongoing
+/// (`true`) or has just finished with `CAP END` (`false`).
+async fn handle_cap(
+    stream: &mut Framed<TcpStream, IrcCodec>,
+    subcommand: &irc::client::prelude::CapSubCommand,
+    param: Option<&str>,
+    capabilities: &mut Capabilities,
+) -> Result<bool> {
+    use irc::client::prelude::CapSubCommand::*;
+    match subcommand {
+        LS => {
+            proto::send_raw_msg(
+                stream,
+                format!(":matrirc CAP * LS :{}", SUPPORTED_CAPS.join(" ")),
+            )
+            .await?;
+            Ok(true)
+        }
+        LIST => {
+            let enabled = SUPPORTED_CAPS
+                .iter()
+                .filter(|cap| capabilities.has(cap))
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(" ");
+            proto::send_raw_msg(stream, format!(":matrirc CAP * LIST :{}", enabled)).await?;
+            Ok(true)
+        }
+        REQ => {
+            let requested: Vec<&str> = param.unwrap_or_default().split_whitespace().collect();
+            let supported = requested
+                .iter()
+                .all(|cap| SUPPORTED_CAPS.contains(cap));
+            if supported {
+                for cap in &requested {
+                    capabilities.enable(cap);
+                }
+                proto::send_raw_msg(
+                    stream,
+                    format!(":matrirc CAP * ACK :{}", requested.join(" ")),
+                )
+                .await?;
+            } else {
+                proto::send_raw_msg(
+                    stream,
+                    format!(":matrirc CAP * NAK :{}", requested.join(" ")),
+                )
+                .await?;
+            }
+            Ok(true)
+        }
+        END => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// Both login paths below hand `nick` down into `matrix::login`, which
+// gives the `Client` it builds a persistent sqlite store keyed off that
+// nick (so `state::Session`/crypto state round-trip across reconnects)
+// and turns on encryption support. Once `auth_loop`'s caller has wrapped
+// the resulting client in an `IrcClient`, it must call
+// `matrix::verification::run` to start handling incoming SAS requests,
+// otherwise verification requests from other devices are never seen.
+
 async fn matrix_login_loop(
     stream: &mut Framed<TcpStream, IrcCodec>,
     nick: &str,
@@ -0,0 +1,158 @@
+use anyhow::Result;
+use irc::{client::prelude::Command, proto::IrcCodec, proto::Message};
+use std::collections::HashSet;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// ircv3 capabilities negotiated for a single connection, via the
+/// `CAP LS`/`CAP REQ`/`CAP END` dance in `ircd::login::auth_loop`.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    enabled: HashSet<String>,
+}
+
+impl Capabilities {
+    pub fn enable(&mut self, cap: &str) {
+        self.enabled.insert(cap.to_string());
+    }
+
+    pub fn has(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+}
+
+/// Kind of irc command a bridged line should be rendered as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrcMessageType {
+    Privmsg,
+    Notice,
+}
+
+/// A line bound for the irc client, carrying enough context to be
+/// turned into the actual wire command (and, for replayed history,
+/// the ircv3 tags it should be sent with).
+#[derive(Debug, Clone)]
+pub struct IrcMessage {
+    pub message_type: IrcMessageType,
+    pub from: String,
+    pub target: String,
+    pub message: String,
+    /// ircv3 message tags, e.g. `("time", "2023-01-02T15:04:05.000Z")`.
+    /// Only actually written out for clients that negotiated the
+    /// matching capability.
+    pub tags: Vec<(String, String)>,
+}
+
+impl From<IrcMessage> for Message {
+    fn from(msg: IrcMessage) -> Self {
+        let command = match msg.message_type {
+            IrcMessageType::Privmsg => Command::PRIVMSG(msg.target, msg.message),
+            IrcMessageType::Notice => Command::NOTICE(msg.target, msg.message),
+        };
+        let mut message = Message::with_prefix(
+            Some(irc::client::prelude::Prefix::Nickname(
+                msg.from,
+                String::new(),
+                String::new(),
+            )),
+            command,
+        );
+        if !msg.tags.is_empty() {
+            message.tags = Some(
+                msg.tags
+                    .into_iter()
+                    .map(|(key, value)| irc::proto::message::Tag(key, Some(value)))
+                    .collect(),
+            );
+        }
+        message
+    }
+}
+
+/// format a matrix timestamp as the `server-time` tag value (ircv3 wants
+/// millisecond-precision RFC3339, e.g. `2011-10-19T16:40:51.620Z`).
+pub fn server_time_tag(ts: matrix_sdk::ruma::MilliSecondsSinceUnixEpoch) -> String {
+    let millis: i64 = ts.get().into();
+    let secs = millis / 1000;
+    let ms = millis % 1000;
+    let datetime =
+        time::OffsetDateTime::from_unix_timestamp(secs).unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{ms:03}Z",
+        year = datetime.year(),
+        month = u8::from(datetime.month()),
+        day = datetime.day(),
+        hour = datetime.hour(),
+        minute = datetime.minute(),
+        second = datetime.second(),
+        ms = ms,
+    )
+}
+
+/// build the ircv3 tag set for a relayed line, given what the client
+/// negotiated: currently just `server-time`, gated on the event's own
+/// `origin_server_ts` rather than wall-clock time.
+pub fn tags_for(
+    capabilities: &Capabilities,
+    origin_server_ts: matrix_sdk::ruma::MilliSecondsSinceUnixEpoch,
+) -> Vec<(String, String)> {
+    if capabilities.has("server-time") {
+        vec![("time".into(), server_time_tag(origin_server_ts))]
+    } else {
+        Vec::new()
+    }
+}
+
+pub async fn send_raw_msg<S: Into<String>>(
+    stream: &mut Framed<TcpStream, IrcCodec>,
+    line: S,
+) -> Result<()> {
+    use futures::SinkExt;
+    stream.send(line.into()).await?;
+    Ok(())
+}
+
+/// send a PRIVMSG from `from` to an already-logged-in client, e.g. for
+/// matrirc's own control query once the stream is wrapped in an [`IrcClient`].
+pub async fn send_control_privmsg(
+    irc: &crate::ircd::IrcClient,
+    from: &str,
+    message: &str,
+) -> Result<()> {
+    irc.send(
+        IrcMessage {
+            message_type: IrcMessageType::Privmsg,
+            from: from.to_string(),
+            target: irc.nick.clone(),
+            message: message.to_string(),
+            tags: Vec::new(),
+        }
+        .into(),
+    )
+    .await
+}
+
+pub async fn send_privmsg<F, T, M>(
+    stream: &mut Framed<TcpStream, IrcCodec>,
+    from: F,
+    target: T,
+    message: M,
+) -> Result<()>
+where
+    F: Into<String>,
+    T: Into<String>,
+    M: Into<String>,
+{
+    send_raw_msg(
+        stream,
+        Message::from(IrcMessage {
+            message_type: IrcMessageType::Privmsg,
+            from: from.into(),
+            target: target.into(),
+            message: message.into(),
+            tags: Vec::new(),
+        })
+        .to_string(),
+    )
+    .await
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+
+use crate::ircd::IrcClient;
+use crate::matrix::room_mappings::Mappings;
+
+/// answer a `WHOIS <nick>` with the matrix identity and shared channels
+/// behind a sanitized irc nick, using the numerics real irc servers use.
+pub async fn handle_whois(
+    irc: &IrcClient,
+    mappings: &Mappings,
+    client: &matrix_sdk::Client,
+    nick: &str,
+) -> Result<()> {
+    let Some(whois) = mappings.whois(nick).await else {
+        return send_numeric(irc, 401, format!("{} :No such nick/channel", nick)).await;
+    };
+
+    let homeserver = whois.user_id.server_name().to_string();
+
+    let member = if let Some(first_room) = whois.channels.first() {
+        match mappings.room_id_for_target(first_room).await {
+            Some(room_id) => match client.get_room(&room_id) {
+                Some(room) => room.get_member(&whois.user_id).await.ok().flatten(),
+                None => None,
+            },
+            None => None,
+        }
+    } else {
+        None
+    };
+    let realname = member
+        .as_ref()
+        .map(|member| member.name().to_string())
+        .unwrap_or_else(|| whois.user_id.to_string());
+
+    send_numeric(
+        irc,
+        311,
+        format!(
+            "{} {} {} * :{}",
+            nick,
+            whois.user_id.localpart(),
+            homeserver,
+            realname
+        ),
+    )
+    .await?;
+    send_numeric(irc, 312, format!("{} {} :matrirc bridge", nick, homeserver)).await?;
+
+    if let Some(member) = &member {
+        if let Some(presence) = presence_idle_line(member) {
+            send_numeric(irc, 317, format!("{} {}", nick, presence)).await?;
+        }
+    }
+
+    if !whois.channels.is_empty() {
+        send_numeric(irc, 319, format!("{} :{}", nick, whois.channels.join(" "))).await?;
+    }
+    send_numeric(irc, 318, format!("{} :End of /WHOIS list", nick)).await?;
+    Ok(())
+}
+
+fn presence_idle_line(member: &matrix_sdk::room::RoomMember) -> Option<String> {
+    // matrix_sdk only exposes presence through a separate sync event
+    // (`m.presence`), not on `RoomMember` itself; until that's wired up
+    // through the membership-sync subsystem we have nothing to show here.
+    let _ = member;
+    None
+}
+
+async fn send_numeric(irc: &IrcClient, numeric: u16, rest: String) -> Result<()> {
+    let line = format!(":matrirc {:03} {} {}", numeric, irc.nick, rest);
+    irc.send(line.parse::<irc::proto::Message>()?).await
+}
@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use matrix_sdk::{Client, Session};
+use std::path::PathBuf;
+
+/// where `matrix_sdk`'s sqlite-backed crypto store lives for a given irc
+/// nick, so the device (and whatever e2ee sessions it's built up) survives
+/// matrirc reconnecting instead of minting a fresh, unverified device on
+/// every login.
+fn crypto_store_path(nick: &str) -> PathBuf {
+    PathBuf::from(std::env::var("MATRIRC_DATA_DIR").unwrap_or_else(|_| "./data".into()))
+        .join(format!("{}-crypto", nick))
+}
+
+/// a `Client` pointed at `homeserver`, backed by the nick-keyed sqlite
+/// crypto store, encryption support turned on. `irc_pass` doubles as the
+/// store's passphrase so the keys on disk aren't sitting there in the
+/// clear - it never leaves this process.
+async fn client_builder(homeserver: &str, nick: &str, irc_pass: &str) -> Result<Client> {
+    Client::builder()
+        .homeserver_url(homeserver)
+        .sqlite_store(crypto_store_path(nick), Some(irc_pass))
+        .build()
+        .await
+        .context("building matrix client")
+}
+
+/// log in to `homeserver` with a fresh username/password, against the
+/// crypto store for `nick` (so the device this creates round-trips
+/// across `restore_session` on the next reconnect instead of needing
+/// re-verification every time).
+pub async fn login(
+    homeserver: &str,
+    user: &str,
+    pass: &str,
+    nick: &str,
+    irc_pass: &str,
+) -> Result<Client> {
+    let client = client_builder(homeserver, nick, irc_pass).await?;
+    client
+        .login_username(user, pass)
+        .initial_device_display_name("matrirc")
+        .send()
+        .await
+        .context("matrix login failed")?;
+    Ok(client)
+}
+
+/// restore a previously saved session against the same nick-keyed crypto
+/// store it was created in, so the device stays the one already
+/// verified instead of matrix_sdk minting a new one.
+pub async fn restore_session(
+    homeserver: &str,
+    session: Session,
+    nick: &str,
+    irc_pass: &str,
+) -> Result<Client> {
+    let client = client_builder(homeserver, nick, irc_pass).await?;
+    client
+        .restore_login(session)
+        .await
+        .context("restoring matrix session failed")?;
+    Ok(client)
+}
@@ -0,0 +1,36 @@
+use matrix_sdk::ruma::MxcUri;
+use url::Url;
+
+/// resolves `mxc://` uris to plain `https://` download links against a
+/// single homeserver.
+///
+/// This is the legacy unauthenticated `/_matrix/media/v3/download`
+/// endpoint, not a fetch-and-reproxy through our own logged-in `Client` -
+/// we don't have anywhere to serve re-proxied bytes from. That means it
+/// only actually works against homeservers that still serve that
+/// endpoint without a token; a growing number of deployments deprecate
+/// or disable it, and on those this link 401s instead of opening. The
+/// real fix (matrirc fetching the bytes itself via `Client::media()` and
+/// serving them from something the N900 can reach unauthenticated) needs
+/// an HTTP server this crate doesn't have yet.
+#[derive(Debug, Clone)]
+pub struct MediaResolver {
+    homeserver: Url,
+}
+
+impl MediaResolver {
+    pub fn new(homeserver: Url) -> Self {
+        MediaResolver { homeserver }
+    }
+
+    /// `mxc://<server>/<media-id>` -> `<homeserver>/_matrix/media/v3/download/<server>/<media-id>`
+    pub fn resolve(&self, mxc: &MxcUri) -> Option<Url> {
+        let (server_name, media_id) = mxc.parts().ok()?;
+        self.homeserver
+            .join(&format!(
+                "_matrix/media/v3/download/{}/{}",
+                server_name, media_id
+            ))
+            .ok()
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use irc::proto::Message;
+use matrix_sdk::{
+    room::Room,
+    ruma::events::room::member::{MembershipState, SyncRoomMemberEvent},
+};
+
+use crate::ircd::IrcClient;
+use crate::matrix::room_mappings::Mappings;
+
+/// react to a single `m.room.member` sync event: keep the target's
+/// `Chan` members/names up to date, relay `JOIN`/`PART`/`NICK`/`QUIT` to
+/// the irc client for channels it has joined, and promote/demote the
+/// `RoomTarget` across the query/chan boundary as active membership
+/// crosses 2<->3.
+pub async fn handle_member_event(
+    mappings: &Mappings,
+    irc: &IrcClient,
+    room: &Room,
+    event: &SyncRoomMemberEvent,
+) -> Result<()> {
+    let (user_id, membership, display_name) = match event {
+        SyncRoomMemberEvent::Original(ev) => (
+            ev.state_key.clone(),
+            ev.content.membership.clone(),
+            ev.content.displayname.clone(),
+        ),
+        SyncRoomMemberEvent::Redacted(ev) => (ev.state_key.clone(), MembershipState::Leave, None),
+    };
+    let display_name = display_name.unwrap_or_else(|| user_id.localpart().to_string());
+
+    let target = mappings.room_target(room).await;
+    let was_live_chan = target.is_live_chan().await;
+    let count_before = target.chan_snapshot().await.members.len();
+
+    match membership {
+        MembershipState::Join => {
+            let previous_nick = target.chan_snapshot().await.members.get(&user_id).cloned();
+            let nick = target
+                .with_chan_mut(|chan| chan.add_member(user_id.clone(), &display_name))
+                .await;
+            if was_live_chan {
+                match previous_nick {
+                    Some(old) if old != nick => send_line(irc, format!(":{} NICK :{}", old, nick)).await?,
+                    Some(_) => (), // unchanged, nothing to tell the client
+                    None => send_line(irc, format!(":{}!matrirc JOIN :{}", nick, target.chan_snapshot().await.target)).await?,
+                }
+            }
+        }
+        MembershipState::Leave | MembershipState::Ban => {
+            let removed = target.with_chan_mut(|chan| chan.remove_member(&user_id)).await;
+            if let (true, Some(nick)) = (was_live_chan, removed) {
+                let chan_name = target.chan_snapshot().await.target;
+                let verb = if membership == MembershipState::Ban {
+                    "QUIT :banned"
+                } else {
+                    "QUIT :left"
+                };
+                send_line(irc, format!(":{}!matrirc {} {}", nick, verb, chan_name)).await?;
+            }
+        }
+        // invites aren't active members yet; nothing to track until accepted.
+        _ => return Ok(()),
+    }
+
+    let count_after = target.chan_snapshot().await.members.len();
+    if count_before <= 2 && count_after >= 3 {
+        target.promote_to_chan().await;
+        let chan_name = target.chan_snapshot().await.target;
+        send_line(irc, format!(":{}!matrirc JOIN :{}", irc.nick, chan_name)).await?;
+    } else if count_before >= 3 && count_after <= 2 {
+        let chan_name = target.chan_snapshot().await.target;
+        target.demote_to_query().await;
+        send_line(
+            irc,
+            format!(":{}!matrirc PART {} :not enough active members", irc.nick, chan_name),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn send_line(irc: &IrcClient, line: String) -> Result<()> {
+    irc.send(line.parse::<Message>()?).await
+}
@@ -1,12 +1,16 @@
 use anyhow::{Error, Result};
 use async_trait::async_trait;
+use lazy_static::lazy_static;
 use matrix_sdk::{
     room::Room,
     ruma::events::room::message::{MessageType, RoomMessageEventContent},
 };
+use regex::Regex;
 use serde_json::map::Map;
 
-use crate::matrix::room_mappings::MessageHandler;
+use crate::ircd::proto::IrcMessageType;
+use crate::matrix::media;
+use crate::matrix::room_mappings::{MentionIndex, MessageHandler};
 
 pub enum MatrixMessageType {
     Text,
@@ -16,10 +20,16 @@ pub enum MatrixMessageType {
 
 #[async_trait]
 impl MessageHandler for Room {
-    async fn handle_message(&self, message_type: MatrixMessageType, message: String) -> Result<()> {
+    async fn handle_message(
+        &self,
+        message_type: MatrixMessageType,
+        message: String,
+        mentions: &MentionIndex,
+    ) -> Result<()> {
         if let Room::Joined(joined) = self {
             let content = match message_type {
-                MatrixMessageType::Text => RoomMessageEventContent::text_plain(message),
+                MatrixMessageType::Text => rewrite_mentions(&message, mentions)
+                    .unwrap_or_else(|| RoomMessageEventContent::text_plain(message)),
                 MatrixMessageType::Emote => {
                     RoomMessageEventContent::new(MessageType::new("m.emote", message, Map::new())?)
                 }
@@ -34,4 +44,207 @@ impl MessageHandler for Room {
             )))
         }
     }
-}
\ No newline at end of file
+}
+
+/// scan an outgoing plain-text irc message for `nick:`/`nick,`-prefixed
+/// or inline `@nick` mentions, and if any resolve against `mentions`,
+/// build an html-formatted body linking to the matrix user plus the
+/// `m.mentions` intentional-mentions field so they actually get pinged.
+/// Returns `None` when no nick in the message matched, so the caller
+/// falls back to plain text.
+fn rewrite_mentions(message: &str, mentions: &MentionIndex) -> Option<RoomMessageEventContent> {
+    lazy_static! {
+        static ref MENTION: Regex =
+            Regex::new(r"(?:^([A-Za-z0-9_-]+)[:,] |@([A-Za-z0-9_-]+)\b)").unwrap();
+    }
+
+    let mut html = String::new();
+    let mut plain_end = 0;
+    let mut mentioned = Vec::new();
+    for capture in MENTION.captures_iter(message) {
+        let whole = capture.get(0).unwrap();
+        // group 1 is the anchored "nick: "/"nick, " form, where the nick is
+        // followed by the punctuation+space the regex also ate; group 2 is
+        // the inline "@nick" form, where the nick is the end of the match
+        // and nothing trails it.
+        let (nick, trailing) = match (capture.get(1), capture.get(2)) {
+            (Some(m), _) => (m.as_str(), &whole.as_str()[m.as_str().len()..]),
+            (_, Some(m)) => (m.as_str(), ""),
+            _ => unreachable!("regex always captures group 1 or 2"),
+        };
+        let Some(user_id) = mentions.get(nick) else {
+            continue;
+        };
+        html.push_str(&html_escape(&message[plain_end..whole.start()]));
+        html.push_str(&format!(
+            "<a href=\"https://matrix.to/#/{user_id}\">{nick}</a>"
+        ));
+        html.push_str(&html_escape(trailing));
+        plain_end = whole.end();
+        mentioned.push(user_id.clone());
+    }
+    if mentioned.is_empty() {
+        return None;
+    }
+    html.push_str(&html_escape(&message[plain_end..]));
+
+    let mut content = RoomMessageEventContent::text_html(message, html);
+    content.mentions = Some(matrix_sdk::ruma::events::Mentions::with_user_ids(mentioned));
+    Some(content)
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::user_id;
+
+    #[test]
+    fn rewrite_mentions_none_when_no_nick_matches() {
+        let mentions = MentionIndex::for_test(vec![]);
+        assert!(rewrite_mentions("hello there", &mentions).is_none());
+    }
+
+    #[test]
+    fn rewrite_mentions_leading_colon_form() {
+        let bob = user_id!("@bob:example.org").to_owned();
+        let mentions = MentionIndex::for_test(vec![("bob", bob.clone())]);
+        let content = rewrite_mentions("bob: hi there", &mentions).unwrap();
+        assert_eq!(
+            content.mentions,
+            Some(matrix_sdk::ruma::events::Mentions::with_user_ids(vec![
+                bob
+            ]))
+        );
+    }
+
+    #[test]
+    fn rewrite_mentions_inline_at_form_no_trailing_char_duplicated() {
+        let bob = user_id!("@bob:example.org").to_owned();
+        let mentions = MentionIndex::for_test(vec![("bob", bob.clone())]);
+        let content = rewrite_mentions("hey @bob, got a sec?", &mentions).unwrap();
+        let MessageType::Text(text) = content.msgtype else {
+            panic!("expected text content");
+        };
+        let html = text.formatted.unwrap().body;
+        assert_eq!(
+            html,
+            "hey <a href=\"https://matrix.to/#/@bob:example.org\">bob</a>, got a sec?"
+        );
+    }
+
+    #[test]
+    fn rewrite_mentions_unknown_nick_left_untouched() {
+        let mentions = MentionIndex::for_test(vec![]);
+        assert!(rewrite_mentions("alice: hi", &mentions).is_none());
+        assert!(rewrite_mentions("hey @alice", &mentions).is_none());
+    }
+}
+
+/// plain text/emotes read as a normal line; everything else (matrix
+/// `m.notice`, and our own media descriptions) reads better as a `NOTICE`
+/// so clients don't treat it like something needing a reply.
+pub fn classify_inbound(content: &MessageType) -> IrcMessageType {
+    match content {
+        MessageType::Text(_) | MessageType::Emote(_) => IrcMessageType::Privmsg,
+        _ => IrcMessageType::Notice,
+    }
+}
+
+/// turn an inbound matrix event body into the irc-side text to relay.
+///
+/// `Text`/`Emote`/`Notice` pass their body through unchanged (the
+/// existing behaviour); media types have no irc-native representation so
+/// they're rendered as a one-line description with a plain `https://`
+/// link the N900 client can try to open directly, since it can't do
+/// authenticated `mxc://` downloads itself. See [`media::MediaResolver`]
+/// for the caveat: that link only works on homeservers still serving
+/// unauthenticated legacy media.
+pub fn describe_inbound(content: &MessageType, media_resolver: &media::MediaResolver) -> String {
+    match content {
+        MessageType::Text(text) => text.body.clone(),
+        MessageType::Emote(emote) => emote.body.clone(),
+        MessageType::Notice(notice) => notice.body.clone(),
+        MessageType::Image(image) => describe_media(&image.body, &image.info, &image.source, media_resolver, "image"),
+        MessageType::File(file) => describe_media(&file.body, &file.info, &file.source, media_resolver, "file"),
+        MessageType::Audio(audio) => describe_media(&audio.body, &audio.info, &audio.source, media_resolver, "audio"),
+        MessageType::Video(video) => describe_media(&video.body, &video.info, &video.source, media_resolver, "video"),
+        MessageType::Location(location) => {
+            format!("[location] {}: {}", location.body, location.geo_uri)
+        }
+        other => format!("[unsupported {} message]", other.msgtype()),
+    }
+}
+
+fn describe_media<I>(
+    body: &str,
+    info: &Option<Box<I>>,
+    source: &matrix_sdk::ruma::events::room::MediaSource,
+    media_resolver: &media::MediaResolver,
+    kind: &str,
+) -> String
+where
+    I: MediaInfo,
+{
+    let url = match source {
+        matrix_sdk::ruma::events::room::MediaSource::Plain(mxc) => {
+            media_resolver.resolve(mxc).map(|url| url.to_string())
+        }
+        // encrypted media needs decrypting client-side; nothing we can
+        // link to directly.
+        matrix_sdk::ruma::events::room::MediaSource::Encrypted(_) => None,
+    };
+    let (size, mimetype) = info
+        .as_ref()
+        .map(|info| (info.size(), info.mimetype()))
+        .unwrap_or((None, None));
+    let mut description = format!("[{}] {}", kind, body);
+    if let Some(mimetype) = mimetype {
+        description.push_str(&format!(" ({})", mimetype));
+    }
+    if let Some(size) = size {
+        description.push_str(&format!(" [{} bytes]", size));
+    }
+    match url {
+        Some(url) => {
+            description.push(' ');
+            description.push_str(&url);
+        }
+        None => description.push_str(match source {
+            matrix_sdk::ruma::events::room::MediaSource::Encrypted(_) => " [encrypted, can't link]",
+            matrix_sdk::ruma::events::room::MediaSource::Plain(_) => " [can't link]",
+        }),
+    }
+    description
+}
+
+/// the bits of `ImageInfo`/`FileInfo`/`AudioInfo`/`VideoInfo` we care
+/// about, so `describe_media` doesn't need one copy-pasted branch per
+/// media type.
+trait MediaInfo {
+    fn size(&self) -> Option<u64>;
+    fn mimetype(&self) -> Option<String>;
+}
+
+macro_rules! impl_media_info {
+    ($ty:ty) => {
+        impl MediaInfo for $ty {
+            fn size(&self) -> Option<u64> {
+                self.size.map(Into::into)
+            }
+            fn mimetype(&self) -> Option<String> {
+                self.mimetype.clone()
+            }
+        }
+    };
+}
+
+impl_media_info!(matrix_sdk::ruma::events::room::ImageInfo);
+impl_media_info!(matrix_sdk::ruma::events::room::message::FileInfo);
+impl_media_info!(matrix_sdk::ruma::events::room::message::AudioInfo);
+impl_media_info!(matrix_sdk::ruma::events::room::message::VideoInfo);
@@ -1,9 +1,11 @@
 use anyhow::{Error, Result};
+use async_trait::async_trait;
 use irc::client::prelude::{Command, Message};
 use lazy_static::lazy_static;
 use log::info;
 use matrix_sdk::{
     room::{Room, RoomMember},
+    ruma::events::room::message::MessageType,
     ruma::user_id,
     ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId},
     RoomMemberships,
@@ -15,25 +17,39 @@ use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock, RwLockReadGuard};
 
 use crate::ircd::{
-    proto::{IrcMessage, IrcMessageType},
+    proto::{tags_for, Capabilities, IrcMessage},
     IrcClient,
 };
 use crate::matrirc::Matrirc;
+use crate::matrix::media::MediaResolver;
+use crate::matrix::proto::MatrixMessageType;
+
+/// something that can turn an outgoing irc message into a matrix one for
+/// a given room, given the mention index for that room's channel.
+#[async_trait]
+pub trait MessageHandler {
+    async fn handle_message(
+        &self,
+        message_type: MatrixMessageType,
+        message: String,
+        mentions: &MentionIndex,
+    ) -> Result<()>;
+}
 
 #[derive(Debug, Clone)]
-struct Chan {
+pub(crate) struct Chan {
     /// channel name or query target
-    target: String,
+    pub(crate) target: String,
     /// matrix user -> nick for channel.
     /// display names is a per-channel property, so we need to
     /// remember this for each user individually.
     /// In queries case, any non-trivial member is expanded as <nick> at
     /// the start of the message
-    members: HashMap<OwnedUserId, String>,
+    pub(crate) members: HashMap<OwnedUserId, String>,
     /// list of irc names in channel
     /// used to enforce unicity, and perhaps later to convert
     /// `mentions:` to matric mentions
-    names: HashMap<String, OwnedUserId>,
+    pub(crate) names: HashMap<String, OwnedUserId>,
 }
 
 #[derive(Debug, Clone)]
@@ -71,6 +87,41 @@ enum RoomTargetInner {
     JoiningChan(JoiningChan),
 }
 
+/// case-insensitive nick -> matrix user lookup, snapshotted from a
+/// channel's `Chan::names` so outbound mention-rewriting doesn't need to
+/// hold any lock while it scans the message text.
+#[derive(Debug, Clone, Default)]
+pub struct MentionIndex {
+    by_lower_nick: HashMap<String, OwnedUserId>,
+}
+
+impl MentionIndex {
+    pub fn get(&self, nick: &str) -> Option<&OwnedUserId> {
+        self.by_lower_nick.get(&nick.to_lowercase())
+    }
+
+    /// build an index directly from nick/user pairs, for exercising
+    /// mention-rewriting against a known set of nicks without going
+    /// through a live `Chan` snapshot.
+    #[cfg(test)]
+    pub(crate) fn for_test(pairs: Vec<(&str, OwnedUserId)>) -> Self {
+        MentionIndex {
+            by_lower_nick: pairs
+                .into_iter()
+                .map(|(nick, user_id)| (nick.to_lowercase(), user_id))
+                .collect(),
+        }
+    }
+}
+
+/// result of a nick -> matrix user reverse lookup for `WHOIS`.
+#[derive(Debug, Clone)]
+pub struct WhoisTarget {
+    pub user_id: OwnedUserId,
+    /// matrirc channels (irc side names) the looked-up user shares with us.
+    pub channels: Vec<String>,
+}
+
 #[derive(Default, Debug)]
 pub struct Mappings {
     inner: RwLock<MappingsInner>,
@@ -108,6 +159,40 @@ impl Chan {
     async fn get_member(&self, member_id: &UserId) -> Option<String> {
         self.members.get(member_id).cloned()
     }
+
+    /// register (or re-fetch) a member under a unique sanitized nick
+    /// derived from their display name, resolving collisions with a
+    /// trailing `_` the same way irc clients do. If the member is already
+    /// known but their display name changed, re-derives and claims a new
+    /// nick, freeing up the old one.
+    pub(crate) fn add_member(&mut self, user_id: OwnedUserId, display_name: &str) -> String {
+        let base = sanitize(display_name);
+        let base = if base.is_empty() {
+            sanitize(user_id.localpart())
+        } else {
+            base
+        };
+        if let Some(nick) = self.members.get(&user_id) {
+            if nick == &base {
+                return nick.clone();
+            }
+            self.names.remove(nick);
+        }
+        let mut nick = base;
+        while self.names.contains_key(&nick) {
+            nick.push('_');
+        }
+        self.members.insert(user_id.clone(), nick.clone());
+        self.names.insert(nick.clone(), user_id);
+        nick
+    }
+
+    /// drop a member, returning the nick they were last known by.
+    pub(crate) fn remove_member(&mut self, user_id: &UserId) -> Option<String> {
+        let nick = self.members.remove(user_id)?;
+        self.names.remove(&nick);
+        Some(nick)
+    }
 }
 
 impl JoiningChan {
@@ -154,11 +239,76 @@ impl RoomTarget {
         Ok(messages)
     }
 
+    /// snapshot of the `Chan` this target currently wraps, whatever
+    /// state it's in (joining, joined, left...): membership data is
+    /// shared between all of them.
+    pub(crate) async fn chan_snapshot(&self) -> Chan {
+        match &*self.inner.read().await {
+            RoomTargetInner::Query(chan)
+            | RoomTargetInner::Chan(chan)
+            | RoomTargetInner::LeftChan(chan) => chan.clone(),
+            RoomTargetInner::JoiningChan(jchan) => jchan.chan.clone(),
+        }
+    }
+
     fn set_error(mut self, error: String) -> Self {
         self.error = Arc::new(Some(error));
         self
     }
 
+    /// snapshot this target's names map for mention-rewriting on an
+    /// outgoing message, see [`MentionIndex`].
+    pub async fn mention_index(&self) -> MentionIndex {
+        let chan = self.chan_snapshot().await;
+        MentionIndex {
+            by_lower_nick: chan
+                .names
+                .into_iter()
+                .map(|(nick, user_id)| (nick.to_lowercase(), user_id))
+                .collect(),
+        }
+    }
+
+    /// mutate the `Chan` wrapped by this target, whichever state it's in.
+    pub(crate) async fn with_chan_mut<R>(&self, f: impl FnOnce(&mut Chan) -> R) -> R {
+        match &mut *self.inner.write().await {
+            RoomTargetInner::Query(chan)
+            | RoomTargetInner::Chan(chan)
+            | RoomTargetInner::LeftChan(chan) => f(chan),
+            RoomTargetInner::JoiningChan(jchan) => f(&mut jchan.chan),
+        }
+    }
+
+    /// whether the target is currently a joined channel on the irc side,
+    /// i.e. membership changes should be relayed as `JOIN`/`PART`/`QUIT`.
+    pub(crate) async fn is_live_chan(&self) -> bool {
+        matches!(&*self.inner.read().await, RoomTargetInner::Chan(_))
+    }
+
+    /// flip a `Query` over to a live `Chan` in place, carrying its members
+    /// across, once active membership crosses the 2->3 boundary. No
+    /// `JoiningChan` detour needed here: the room is already an active
+    /// matrix membership (that's how we got the member event at all), so
+    /// only the irc-side presentation changes, not the matrix join state.
+    pub(crate) async fn promote_to_chan(&self) {
+        let mut lock = self.inner.write().await;
+        if let RoomTargetInner::Query(chan) = &*lock {
+            *lock = RoomTargetInner::Chan(chan.clone());
+        }
+    }
+
+    /// flip a `Chan`/`LeftChan`/`JoiningChan` back down to a `Query` in
+    /// place, once active membership drops back to the 2->3 boundary.
+    pub(crate) async fn demote_to_query(&self) {
+        let mut lock = self.inner.write().await;
+        let chan = match &*lock {
+            RoomTargetInner::Chan(chan) | RoomTargetInner::LeftChan(chan) => chan.clone(),
+            RoomTargetInner::JoiningChan(jchan) => jchan.chan.clone(),
+            RoomTargetInner::Query(_) => return,
+        };
+        *lock = RoomTargetInner::Query(chan);
+    }
+
     async fn target_of_room(name: String, room: &Room) -> Result<(Self, Vec<RoomMember>)> {
         // XXX we don't want this to be long: figure out active_members_count
         // https://github.com/matrix-org/matrix-rust-sdk/issues/2010
@@ -170,26 +320,29 @@ impl RoomTarget {
         }
     }
 
-    pub async fn send_irc_message<'a, S>(
+    pub async fn send_irc_message(
         &self,
         irc: &IrcClient,
-        message_type: IrcMessageType,
         sender_id: &UserId,
-        message: S,
-    ) -> Result<()>
-    where
-        S: Into<String> + std::fmt::Display,
-    {
+        content: &MessageType,
+        media_resolver: &MediaResolver,
+        capabilities: &Capabilities,
+        origin_server_ts: matrix_sdk::ruma::MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        let message_type = crate::matrix::proto::classify_inbound(content);
+        let body = crate::matrix::proto::describe_inbound(content, media_resolver);
+        let tags = tags_for(capabilities, origin_server_ts);
         let message: Message = match &*self.inner.read().await {
             RoomTargetInner::Query(target) => IrcMessage {
                 message_type,
                 from: target.target.clone(),
                 target: irc.nick.clone(),
                 message: if let Some(nick) = target.members.get(sender_id) {
-                    format!("<{}> {}", nick, message)
+                    format!("<{}> {}", nick, body)
                 } else {
-                    message.into()
+                    body
                 },
+                tags,
             },
 
             // XXX chans are still queries at this point
@@ -203,8 +356,9 @@ impl RoomTarget {
                         .get(sender_id)
                         .map(Cow::Borrowed)
                         .unwrap_or_else(|| Cow::Owned(sender_id.to_string())),
-                    message
+                    body
                 ),
+                tags,
             },
             // This one should trigger a join and queue message
             RoomTargetInner::LeftChan(chan) => IrcMessage {
@@ -217,8 +371,9 @@ impl RoomTarget {
                         .get(sender_id)
                         .map(Cow::Borrowed)
                         .unwrap_or_else(|| Cow::Owned(sender_id.to_string())),
-                    message
+                    body
                 ),
+                tags,
             },
             // This one should just queue message
             RoomTargetInner::JoiningChan(jchan) => IrcMessage {
@@ -233,8 +388,9 @@ impl RoomTarget {
                         .get(sender_id)
                         .map(Cow::Borrowed)
                         .unwrap_or_else(|| Cow::Owned(sender_id.to_string())),
-                    message
+                    body
                 ),
+                tags,
             },
         }
         .into();
@@ -294,10 +450,94 @@ impl Mappings {
         // XXX: start task to start join process (needs irc...)
         Ok(target)
     }
-    // XXX promote/demote chans on join/leave events:
-    // 1 -> 2 active, check for name/rename query
-    // 2 -> 3+, convert from query to chan
-    // 3+ -> 3, demote to query?
-    // 2 -> 1, rename to avoid confusion?
-    // XXX update room mappings on join/leave events...
+    /// Resolve a chan/query name (as seen on the irc side) back to the
+    /// matrix room id it's mapped to, e.g. for CHATHISTORY lookups.
+    pub async fn room_id_for_target(&self, target: &str) -> Option<OwnedRoomId> {
+        self.inner
+            .read()
+            .await
+            .targets
+            .iter()
+            .find(|(name, _)| sanitize(name.as_str()) == target)
+            .map(|(_, room_id)| (**room_id).clone())
+    }
+    /// reverse-lookup a sanitized irc nick to the matrix user behind it,
+    /// along with every matrirc channel they're visible in, for `WHOIS`.
+    pub async fn whois(&self, nick: &str) -> Option<WhoisTarget> {
+        let rooms = self.inner.read().await.rooms.clone();
+        let mut user_id = None;
+        let mut channels = Vec::new();
+        for target in rooms.values() {
+            let chan = target.chan_snapshot().await;
+            match chan.names.get(nick) {
+                // first room we see this nick in pins the user_id; later
+                // rooms only count as "shared" if the name resolves to
+                // that same matrix user, since `_`-suffix dedup only
+                // guarantees uniqueness within a single Chan, not globally.
+                Some(id) if user_id.is_none() => {
+                    user_id = Some(id.clone());
+                    channels.push(chan.target);
+                }
+                Some(id) if user_id.as_ref() == Some(id) => {
+                    channels.push(chan.target);
+                }
+                _ => {}
+            }
+        }
+        user_id.map(|user_id| WhoisTarget { user_id, channels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::user_id;
+
+    #[test]
+    fn sanitize_strips_anything_not_letter_dash_underscore() {
+        assert_eq!(sanitize("Bob Smith!"), "BobSmith");
+        assert_eq!(sanitize("日本語"), "");
+        assert_eq!(sanitize("a_b-c"), "a_b-c");
+    }
+
+    #[test]
+    fn add_member_dedupes_collisions_with_trailing_underscore() {
+        let mut chan = Chan::new("#room".into());
+        let bob = user_id!("@bob1:example.org").to_owned();
+        let other_bob = user_id!("@bob2:example.org").to_owned();
+
+        assert_eq!(chan.add_member(bob.clone(), "Bob"), "Bob");
+        assert_eq!(chan.add_member(other_bob.clone(), "Bob"), "Bob_");
+        assert_eq!(chan.names.get("Bob"), Some(&bob));
+        assert_eq!(chan.names.get("Bob_"), Some(&other_bob));
+    }
+
+    #[test]
+    fn add_member_reuses_nick_when_display_name_unchanged() {
+        let mut chan = Chan::new("#room".into());
+        let bob = user_id!("@bob:example.org").to_owned();
+
+        assert_eq!(chan.add_member(bob.clone(), "Bob"), "Bob");
+        assert_eq!(chan.add_member(bob.clone(), "Bob"), "Bob");
+        assert_eq!(chan.names.len(), 1);
+    }
+
+    #[test]
+    fn add_member_frees_old_nick_on_display_name_change() {
+        let mut chan = Chan::new("#room".into());
+        let bob = user_id!("@bob:example.org").to_owned();
+
+        assert_eq!(chan.add_member(bob.clone(), "Bob"), "Bob");
+        assert_eq!(chan.add_member(bob.clone(), "Robert"), "Robert");
+        assert!(chan.names.get("Bob").is_none());
+        assert_eq!(chan.names.get("Robert"), Some(&bob));
+    }
+
+    #[test]
+    fn add_member_falls_back_to_localpart_when_display_name_sanitizes_empty() {
+        let mut chan = Chan::new("#room".into());
+        let bob = user_id!("@bob:example.org").to_owned();
+
+        assert_eq!(chan.add_member(bob, "日本語"), "bob");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use anyhow::Result;
+use log::info;
+use matrix_sdk::encryption::verification::{SasVerification, Verification};
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
+use matrix_sdk::Client;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::ircd::proto::send_control_privmsg;
+use crate::ircd::IrcClient;
+
+// This module covers the SAS-over-IRC half of the "enable E2EE" request:
+// reacting to an incoming verification request and relaying it through
+// matrirc's control query. The other half - building the `Client` with a
+// persistent crypto store and restoring/tracking the device across
+// reconnects - lives in `matrix::login`/`state::Session`, which is what
+// gives `sas.accept()` below a real, persisted device to attach to.
+
+/// matrirc's own control query, reused here the same way the login flow
+/// prompts the user for credentials.
+const CONTROL_QUERY: &str = "matrirc";
+
+/// tracks the one SAS verification matrirc is asking the irc user about
+/// at a time, so the control-query PRIVMSG handler can drive it from
+/// plain `confirm`/`cancel` replies.
+#[derive(Default)]
+pub struct PendingVerifications {
+    current: Mutex<Option<SasVerification>>,
+}
+
+impl PendingVerifications {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// called by the per-connection PRIVMSG dispatcher when a reply
+    /// arrives on matrirc's control query; returns whether a verification
+    /// was pending and so consumed the reply. Anything other than
+    /// `confirm`/`cancel` gets a re-prompt and leaves it pending.
+    pub async fn handle_reply(&self, irc: &IrcClient, reply: &str) -> Result<bool> {
+        let mut slot = self.current.lock().await;
+        let Some(sas) = slot.take() else {
+            return Ok(false);
+        };
+        match reply.trim() {
+            "confirm" => sas.confirm().await?,
+            "cancel" => sas.cancel().await?,
+            _ => {
+                send_control_privmsg(irc, CONTROL_QUERY, "Reply 'confirm' or 'cancel'.").await?;
+                *slot = Some(sas);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// subscribe to incoming SAS verification requests for the session's
+/// device, and drive them interactively through `matrirc`'s control
+/// query: print the emoji, then wait for [`PendingVerifications::handle_reply`]
+/// to see a `confirm`/`cancel` decision.
+///
+/// Decrypted room messages themselves need no special handling once the
+/// client is verified: they flow through the normal sync -> `send_irc_message`
+/// path unchanged, matrix_sdk decrypts transparently.
+pub async fn run(client: Client, irc: IrcClient, pending: Arc<PendingVerifications>) {
+    client.add_event_handler(
+        move |event: ToDeviceKeyVerificationRequestEvent, client: Client| {
+            let irc = irc.clone();
+            let pending = pending.clone();
+            async move {
+                if let Err(e) = handle_request(&client, &irc, &pending, event).await {
+                    info!("verification request handling failed: {}", e);
+                }
+            }
+        },
+    );
+}
+
+async fn handle_request(
+    client: &Client,
+    irc: &IrcClient,
+    pending: &PendingVerifications,
+    event: ToDeviceKeyVerificationRequestEvent,
+) -> Result<()> {
+    let Some(request) = client
+        .encryption()
+        .get_verification_request(&event.sender, &event.content.transaction_id)
+        .await
+    else {
+        return Ok(());
+    };
+    request.accept().await?;
+
+    let Some(Verification::SasV1(sas)) = request.start_sas().await? else {
+        return Ok(());
+    };
+    sas.accept().await?;
+
+    // not every device supports emoji SAS, only the decimal fallback;
+    // if we can't show the user anything to compare, there's nothing
+    // for a later confirm/cancel reply to meaningfully apply to.
+    let display = if let Some(emojis) = sas.emoji() {
+        emojis
+            .iter()
+            .map(|e| format!("{} ({})", e.symbol, e.description))
+            .collect::<Vec<_>>()
+            .join("  ")
+    } else if let Some((a, b, c)) = sas.decimals() {
+        format!("{} {} {}", a, b, c)
+    } else {
+        sas.cancel().await?;
+        return Ok(());
+    };
+    send_control_privmsg(
+        irc,
+        CONTROL_QUERY,
+        &format!(
+            "Verification request from {}. Compare: {}. Reply 'confirm' or 'cancel'.",
+            event.sender, display
+        ),
+    )
+    .await?;
+    *pending.current.lock().await = Some(sas);
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// where matrirc keeps its per-nick state: the matrix session needed to
+/// reconnect without a fresh login, alongside the sqlite crypto store
+/// `matrix::login` opens under the same nick.
+fn data_dir() -> PathBuf {
+    PathBuf::from(std::env::var("MATRIRC_DATA_DIR").unwrap_or_else(|_| "./data".into()))
+}
+
+fn session_path(nick: &str) -> PathBuf {
+    data_dir().join(format!("{}.json", nick))
+}
+
+/// the matrix-side half of a logged-in irc user: which homeserver they're
+/// on and the session matrix_sdk needs to pick back up without logging in
+/// again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub homeserver: String,
+    pub matrix_session: matrix_sdk::Session,
+}
+
+/// look up a previously logged-in nick, returning its saved session if
+/// one exists. `pass` isn't checked here - it's the passphrase
+/// `matrix::login` hands the sqlite crypto store, so a wrong one just
+/// fails to open that store rather than failing this lookup.
+pub fn login(nick: &str, _pass: &str) -> Result<Option<Session>> {
+    let path = session_path(nick);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data =
+        fs::read_to_string(&path).with_context(|| format!("reading session for {}", nick))?;
+    Ok(Some(
+        serde_json::from_str(&data).with_context(|| format!("parsing session for {}", nick))?,
+    ))
+}
+
+/// persist a freshly logged-in nick's session so future reconnects can
+/// skip straight to `login` returning `Some`.
+pub fn create_user(nick: &str, _pass: &str, session: Session) -> Result<()> {
+    let dir = data_dir();
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let data = serde_json::to_string(&session)?;
+    fs::write(session_path(nick), data)
+        .with_context(|| format!("writing session for {}", nick))
+}